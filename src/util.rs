@@ -1,17 +1,71 @@
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 use either::Either;
 use inflections::Inflect;
-use svd::{Access, EnumeratedValues, Field, Peripheral, Register, RegisterInfo,
-          Usage};
+use svd::{Access, Cluster, ClusterInfo, EnumeratedValues, Field, Peripheral,
+          Register, RegisterCluster, RegisterInfo, Usage};
 use syn::{Ident, IntTy, Lit};
 
 use errors::*;
 
-/// List of chars that some vendors use in their peripheral/field names but
-/// that are not valid in Rust ident
-const BLACKLIST_CHARS: &'static [char] = &['(', ')'];
+/// Replaces every char that is not valid in a Rust identifier (anything outside
+/// `[A-Za-z0-9_]`, as found in vendor names: `[`, `]`, `%`, `/`, `-`, `.`, `+`,
+/// spaces, `(`, `)`, ...) with `_`, collapsing runs so `FOO (%s)` does not turn
+/// into a pile of adjacent underscores.
+fn sanitize(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut last_underscore = false;
+
+    for c in s.chars() {
+        match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => {
+                out.push(c);
+                last_underscore = c == '_';
+            }
+            _ => {
+                if !last_underscore {
+                    out.push('_');
+                    last_underscore = true;
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Makes a set of already-sanitized identifiers unique by appending `_1`, `_2`,
+/// ... to every name that has already appeared, in declaration order. The first
+/// occurrence of a name is left untouched and the suffixing skips values that
+/// would themselves collide, so the result is deterministic for a given input.
+///
+/// Intended to be run over any group of names that must be distinct yet can
+/// sanitize from two different vendor names down to the same identifier: fields
+/// within a register, registers within a peripheral, variants within a field.
+pub fn make_unique(names: &mut [String]) {
+    let originals: HashSet<String> = names.iter().cloned().collect();
+    let mut seen = HashSet::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+
+    for name in names.iter_mut() {
+        if seen.insert(name.clone()) {
+            continue;
+        }
+
+        let count = counts.entry(name.clone()).or_insert(0);
+        let candidate = loop {
+            *count += 1;
+            let candidate = format!("{}_{}", name, *count);
+            if !originals.contains(&candidate) && seen.insert(candidate.clone()) {
+                break candidate;
+            }
+        };
+
+        *name = candidate;
+    }
+}
 
 pub trait ToSanitizedPascalCase {
     fn to_sanitized_pascal_case(&self) -> Cow<str>;
@@ -32,7 +86,7 @@ impl ToSanitizedSnakeCase for str {
             }
         }
 
-        let s = self.replace(BLACKLIST_CHARS, "");
+        let s = sanitize(self);
 
         match s.chars().next().unwrap_or('\0') {
             '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
@@ -100,7 +154,7 @@ impl ToSanitizedSnakeCase for str {
 
 impl ToSanitizedPascalCase for str {
     fn to_sanitized_pascal_case(&self) -> Cow<str> {
-        let s = self.replace(BLACKLIST_CHARS, "");
+        let s = sanitize(self);
 
         match s.chars().next().unwrap_or('\0') {
             '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
@@ -123,65 +177,121 @@ pub struct ExpandedRegister<'a> {
     pub ty: Either<String, Rc<String>>,
 }
 
-/// Takes a list of "registers", some of which may actually be register arrays,
-/// and turns it into a new *sorted* (by address offset) list of registers where
-/// the register arrays have been expanded.
-pub fn expand(registers: &[Register]) -> Vec<ExpandedRegister> {
+/// A single node in the tree produced by [`expand`]: either a leaf register
+/// (possibly one element of an expanded register array) or a cluster whose own
+/// `children` have been expanded relative to the cluster's absolute offset.
+pub enum ExpandedEntity<'a> {
+    Register(ExpandedRegister<'a>),
+    Cluster {
+        name: String,
+        ty: String,
+        offset: u32,
+        children: Vec<ExpandedEntity<'a>>,
+    },
+}
+
+impl<'a> ExpandedEntity<'a> {
+    /// The entity's offset, absolute within the owning peripheral.
+    pub fn offset(&self) -> u32 {
+        match *self {
+            ExpandedEntity::Register(ref r) => r.offset,
+            ExpandedEntity::Cluster { offset, .. } => offset,
+        }
+    }
+}
+
+/// Takes a peripheral's register/cluster tree, some entries of which may
+/// actually be register or cluster *arrays*, and turns it into a new *sorted*
+/// (by address offset) tree of entities where the arrays have been expanded.
+///
+/// Expansion recurses into clusters, accumulating each cluster's
+/// `address_offset` into its children so that the offset computed for a deeply
+/// nested register is absolute within the owning peripheral. The sort is stable,
+/// so entities at the same offset keep their declared order, and a cluster's
+/// registers stay contiguous inside its `children`.
+pub fn expand(ercs: &[RegisterCluster]) -> Vec<ExpandedEntity> {
+    expand_at(ercs, 0)
+}
+
+fn expand_at(ercs: &[RegisterCluster], base_offset: u32) -> Vec<ExpandedEntity> {
     let mut out = vec![];
 
-    for r in registers {
-        match *r {
-            Register::Single(ref info) => {
-                out.push(
+    for erc in ercs {
+        match *erc {
+            RegisterCluster::Register(ref r) => {
+                expand_register(r, base_offset, &mut out)
+            }
+            RegisterCluster::Cluster(ref c) => {
+                expand_cluster(c, base_offset, &mut out)
+            }
+        }
+    }
+
+    out.sort_by_key(|e| e.offset());
+
+    out
+}
+
+fn expand_register<'a>(
+    r: &'a Register,
+    base_offset: u32,
+    out: &mut Vec<ExpandedEntity<'a>>,
+) {
+    match *r {
+        Register::Single(ref info) => {
+            out.push(
+                ExpandedEntity::Register(
                     ExpandedRegister {
                         register: r,
                         info: info,
                         name: info.name.to_sanitized_snake_case().into_owned(),
-                        offset: info.address_offset,
+                        offset: base_offset + info.address_offset,
                         ty: Either::Left(
                             info.name
                                 .to_sanitized_pascal_case()
                                 .into_owned(),
                         ),
                     },
-                )
-            }
-            Register::Array(ref info, ref array_info) => {
-                let has_brackets = info.name.contains("[%s]");
+                ),
+            )
+        }
+        Register::Array(ref info, ref array_info) => {
+            let has_brackets = info.name.contains("[%s]");
 
-                let ty = if has_brackets {
-                    info.name.replace("[%s]", "")
+            let ty = if has_brackets {
+                info.name.replace("[%s]", "")
+            } else {
+                info.name.replace("%s", "")
+            };
+
+            let ty = Rc::new(ty.to_sanitized_pascal_case().into_owned());
+
+            let indices = array_info
+                .dim_index
+                .as_ref()
+                .map(|v| Cow::from(&**v))
+                .unwrap_or_else(
+                    || {
+                        Cow::from(
+                            (0..array_info.dim)
+                                .map(|i| i.to_string())
+                                .collect::<Vec<_>>(),
+                        )
+                    },
+                );
+
+            for (idx, i) in indices.iter().zip(0..) {
+                let name = if has_brackets {
+                    info.name.replace("[%s]", idx)
                 } else {
-                    info.name.replace("%s", "")
+                    info.name.replace("%s", idx)
                 };
 
-                let ty = Rc::new(ty.to_sanitized_pascal_case().into_owned());
-
-                let indices = array_info
-                    .dim_index
-                    .as_ref()
-                    .map(|v| Cow::from(&**v))
-                    .unwrap_or_else(
-                        || {
-                            Cow::from(
-                                (0..array_info.dim)
-                                    .map(|i| i.to_string())
-                                    .collect::<Vec<_>>(),
-                            )
-                        },
-                    );
-
-                for (idx, i) in indices.iter().zip(0..) {
-                    let name = if has_brackets {
-                        info.name.replace("[%s]", idx)
-                    } else {
-                        info.name.replace("%s", idx)
-                    };
+                let offset = base_offset + info.address_offset +
+                             i * array_info.dim_increment;
 
-                    let offset = info.address_offset +
-                                 i * array_info.dim_increment;
-
-                    out.push(
+                out.push(
+                    ExpandedEntity::Register(
                         ExpandedRegister {
                             register: r,
                             info: info,
@@ -189,15 +299,74 @@ pub fn expand(registers: &[Register]) -> Vec<ExpandedRegister> {
                             offset: offset,
                             ty: Either::Right(ty.clone()),
                         },
-                    );
-                }
+                    ),
+                );
             }
         }
     }
+}
 
-    out.sort_by_key(|x| x.offset);
+fn expand_cluster<'a>(
+    c: &'a Cluster,
+    base_offset: u32,
+    out: &mut Vec<ExpandedEntity<'a>>,
+) {
+    match *c {
+        Cluster::Single(ref info) => {
+            out.push(
+                cluster_entity(info, &info.name, base_offset + info.address_offset),
+            )
+        }
+        Cluster::Array(ref info, ref array_info) => {
+            let has_brackets = info.name.contains("[%s]");
+
+            let indices = array_info
+                .dim_index
+                .as_ref()
+                .map(|v| Cow::from(&**v))
+                .unwrap_or_else(
+                    || {
+                        Cow::from(
+                            (0..array_info.dim)
+                                .map(|i| i.to_string())
+                                .collect::<Vec<_>>(),
+                        )
+                    },
+                );
 
-    out
+            for (idx, i) in indices.iter().zip(0..) {
+                let name = if has_brackets {
+                    info.name.replace("[%s]", idx)
+                } else {
+                    info.name.replace("%s", idx)
+                };
+
+                let offset = base_offset + info.address_offset +
+                             i * array_info.dim_increment;
+
+                out.push(cluster_entity(info, &name, offset));
+            }
+        }
+    }
+}
+
+fn cluster_entity<'a>(
+    info: &'a ClusterInfo,
+    name: &str,
+    offset: u32,
+) -> ExpandedEntity<'a> {
+    let raw_ty = if info.name.contains("[%s]") {
+        info.name.replace("[%s]", "")
+    } else {
+        info.name.replace("%s", "")
+    };
+
+    ExpandedEntity::Cluster {
+        name: name.to_sanitized_snake_case().into_owned(),
+        ty: raw_ty.to_sanitized_pascal_case().into_owned(),
+        offset: offset,
+        children: expand_at(&info.children, offset),
+    }
 }
 
 pub fn name_of(register: &Register) -> Cow<str> {
@@ -297,6 +466,90 @@ pub fn lookup<'a>
     Ok(evs.first().cloned())
 }
 
+/// Whether `evs` already names every one of a `width`-bit field's `2^width`
+/// possible bit patterns.
+///
+/// When this returns `false` a generated read-enum can't represent an
+/// unexpected hardware value, so the generator emits a catch-all `_Reserved`
+/// variant holding the raw value; when it returns `true` the enum is emitted
+/// exhaustively with no `_Reserved`. A block carrying an `isDefault` entry
+/// covers the remaining patterns by definition and so counts as exhaustive.
+pub fn values_are_exhaustive(evs: &EnumeratedValues, width: u32) -> bool {
+    if evs.values.iter().any(|v| v.is_default == Some(true)) {
+        return true;
+    }
+
+    let defined = evs.values
+        .iter()
+        .filter_map(|v| v.value)
+        .collect::<HashSet<_>>()
+        .len() as u64;
+
+    // A `Vec` of defined values can never reach `2^64`, and the shift would
+    // overflow, so anything that wide is treated as non-exhaustive.
+    match 1u64.checked_shl(width) {
+        Some(total) => defined >= total,
+        None => false,
+    }
+}
+
+/// The `enumeratedValues` blocks backing a field's accessors: a read-side block
+/// (resolved with `Usage::Read`) and a write-side block (`Usage::Write`).
+///
+/// When a field declares two blocks with different meanings — common for
+/// status/clear or command/status fields — the reader and writer get distinct
+/// enums. When only one block exists both entries resolve to it and the
+/// generator falls back to a single shared enum.
+pub struct ReadWriteEnums<'a> {
+    pub read: Option<(&'a EnumeratedValues, Option<Base<'a>>)>,
+    pub write: Option<(&'a EnumeratedValues, Option<Base<'a>>)>,
+}
+
+impl<'a> ReadWriteEnums<'a> {
+    /// Whether the read and write sides resolved to the very same block, in
+    /// which case a single enum suffices for both accessors.
+    pub fn is_shared(&self) -> bool {
+        match (self.read.as_ref(), self.write.as_ref()) {
+            (Some(&(r, _)), Some(&(w, _))) => {
+                r as *const _ == w as *const _
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Resolves the read-side and write-side `enumeratedValues` for a field by
+/// running [`lookup`] once per `Usage`, preserving `derived_from` resolution so
+/// a write block can derive from another field's read block.
+pub fn lookup_read_write<'a>(
+    evs: &'a [EnumeratedValues],
+    fields: &'a [Field],
+    register: &'a Register,
+    all_registers: &'a [Register],
+    peripheral: &'a Peripheral,
+) -> Result<ReadWriteEnums<'a>> {
+    Ok(
+        ReadWriteEnums {
+            read: lookup(
+                evs,
+                fields,
+                register,
+                all_registers,
+                peripheral,
+                Usage::Read,
+            )?,
+            write: lookup(
+                evs,
+                fields,
+                register,
+                all_registers,
+                peripheral,
+                Usage::Write,
+            )?,
+        },
+    )
+}
+
 fn lookup_in_fields<'f>(
     base_evs: &str,
     base_field: &str,
@@ -497,6 +750,7 @@ impl U32Ext for u32 {
                 1...8 => Ident::new("u8"),
                 9...16 => Ident::new("u16"),
                 17...32 => Ident::new("u32"),
+                33...64 => Ident::new("u64"),
                 _ => {
                     Err(
                         format!(
@@ -509,3 +763,40 @@ impl U32Ext for u32 {
         )
     }
 }
+
+/// How a register is accessed once its `size` is compared against the widest
+/// single access the target can perform.
+pub enum RegisterAccess {
+    /// The register fits in a single access of `size.to_ty()` width.
+    Single,
+    /// The register is wider than the bus and is modeled as `count` accesses of
+    /// `chunk` bits each (e.g. a 64-bit register as two 32-bit accesses).
+    Split { chunk: u32, count: u32 },
+}
+
+/// Picks an access strategy for a register of `size` bits given the widest
+/// single access (`bus_width`, typically 32) the target supports.
+///
+/// A register that fits in one access uses [`RegisterAccess::Single`]; a wider
+/// one is split into even `bus_width`-sized chunks. If it is wider than the bus
+/// yet not an exact multiple, neither scheme applies and an error is returned.
+pub fn register_access(size: u32, bus_width: u32) -> Result<RegisterAccess> {
+    if size <= bus_width {
+        Ok(RegisterAccess::Single)
+    } else if size % bus_width == 0 {
+        Ok(
+            RegisterAccess::Split {
+                chunk: bus_width,
+                count: size / bus_width,
+            },
+        )
+    } else {
+        Err(
+            format!(
+                "can't model a {}-bit register with {}-bit accesses",
+                size,
+                bus_width
+            ),
+        )?
+    }
+}